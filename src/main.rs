@@ -1,25 +1,72 @@
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, Path, Query, State},
     http::StatusCode,
-    response::{Html, sse::Event, sse::Sse, IntoResponse},
+    response::{Html, sse::Event, sse::Sse, IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
-use futures::Stream; // Remove StreamExt as it's not used
+use futures::Stream;
+use governor::{Quota, RateLimiter};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::{convert::Infallible, env, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
 use tower_http::services::ServeDir;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use std::process::Command;
+use uuid::Uuid;
 
 // Default Ollama URL, can be overridden with OLLAMA_URL environment variable
 fn get_ollama_url() -> String {
     env::var("OLLAMA_URL").unwrap_or_else(|_| "http://127.0.0.1:11434".to_string())
 }
 
+// Optional bearer token for authenticating against a remote/secured Ollama server
+fn get_ollama_bearer_token() -> Option<String> {
+    env::var("OLLAMA_BEARER_TOKEN").ok()
+}
+
+// Cap on generation requests per second, can be overridden with
+// MAX_REQUESTS_PER_SECOND. Keeps a single misbehaving tab from flooding a
+// local, often low-VRAM, Ollama daemon with overlapping heavy generations.
+const DEFAULT_MAX_REQUESTS_PER_SECOND: f64 = 2.0;
+
+// Upper bound on the configurable rate. Far above any value a real
+// deployment would set, but it keeps `1.0 / rps` from underflowing to a
+// zero `Duration`, which would make `Quota::with_period` build a zero-length
+// period and panic the same way a zero/negative input would.
+const MAX_REQUESTS_PER_SECOND_CEILING: f64 = 1000.0;
+
+fn get_max_requests_per_second() -> f64 {
+    env::var("MAX_REQUESTS_PER_SECOND")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        // A zero, negative, non-finite, or absurdly large value would turn
+        // into a zero/negative/infinite/underflowed-to-zero Duration below
+        // and panic before the quota is ever built, so fall back instead.
+        .filter(|rps| rps.is_finite() && *rps > 0.0 && *rps <= MAX_REQUESTS_PER_SECOND_CEILING)
+        .unwrap_or(DEFAULT_MAX_REQUESTS_PER_SECOND)
+}
+
+// Keyed by client IP so one busy tab/user only throttles itself, not every
+// other client talking to this server.
+type GenerationRateLimiter = governor::DefaultKeyedRateLimiter<IpAddr>;
+
+// How often to sweep idle per-IP entries out of the rate limiter. Without
+// this, every distinct IP that ever hits a generation endpoint stays in the
+// map for the life of the process - a slow unbounded-memory leak on a
+// gateway-fronted instance that sees many client IPs over time.
+const RATE_LIMITER_GC_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Model {
     name: String,
@@ -39,6 +86,46 @@ struct ConfigResponse {
 struct GenerateRequest {
     model: String,
     prompt: String,
+    #[serde(default)]
+    system: Option<String>,
+    #[serde(default)]
+    options: Option<OllamaOptions>,
+}
+
+// Sampling / context-window options forwarded to Ollama's `options` object.
+// `num_ctx` defaults to 4096 since Ollama has no API to query a model's max
+// context; callers can override it per-request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaOptions {
+    #[serde(default = "default_num_ctx")]
+    num_ctx: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    num_predict: Option<i32>,
+}
+
+fn default_num_ctx() -> u32 {
+    4096
+}
+
+impl Default for OllamaOptions {
+    fn default() -> Self {
+        Self {
+            num_ctx: default_num_ctx(),
+            temperature: None,
+            top_p: None,
+            seed: None,
+            stop: None,
+            num_predict: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -46,6 +133,9 @@ struct OllamaRequest {
     model: String,
     prompt: String,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    options: OllamaOptions,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -54,6 +144,53 @@ struct OllamaChunk {
     done: Option<bool>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct PullRequest {
+    name: String,
+}
+
+// Ollama's `/api/pull` emits progress objects like
+// `{ "status": "...", "total": ..., "completed": ... }` as it downloads.
+#[derive(Debug, Serialize)]
+struct OllamaPullRequest {
+    name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    options: Option<OllamaOptions>,
+    #[serde(default)]
+    generation_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    options: OllamaOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaChatMessage {
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaChatChunk {
+    message: Option<OllamaChatMessage>,
+    done: Option<bool>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct FileContext {
     name: String,
@@ -65,12 +202,147 @@ struct StreamWithFilesRequest {
     model: String,
     prompt: String,
     files: Vec<FileContext>,
+    #[serde(default)]
+    system: Option<String>,
+    #[serde(default)]
+    options: Option<OllamaOptions>,
+    #[serde(default)]
+    max_context_chunks: Option<usize>,
+    #[serde(default)]
+    generation_id: Option<String>,
+}
+
+// A chunk of a file's content, carried alongside its source filename so
+// retrieved context can be labeled back to where it came from.
+struct ScoredChunk<'a> {
+    file_name: &'a str,
+    text: String,
+    score: f32,
+}
+
+const CHUNK_WORDS: usize = 500;
+const CHUNK_OVERLAP_WORDS: usize = 50;
+const DEFAULT_MAX_CONTEXT_CHUNKS: usize = 5;
+
+// Split content into overlapping ~`CHUNK_WORDS`-word windows. Words are used
+// as a cheap proxy for tokens since we don't have the model's tokenizer here.
+fn chunk_content(content: &str) -> Vec<String> {
+    let words: Vec<&str> = content.split_whitespace().collect();
+    if words.len() <= CHUNK_WORDS {
+        return vec![content.to_string()];
+    }
+
+    let stride = CHUNK_WORDS - CHUNK_OVERLAP_WORDS;
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < words.len() {
+        let end = (start + CHUNK_WORDS).min(words.len());
+        chunks.push(words[start..end].join(" "));
+        if end == words.len() {
+            break;
+        }
+        start += stride;
+    }
+    chunks
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EmbeddingRequest {
+    model: String,
+    input: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EmbeddingResponse {
+    embeddings: Vec<Vec<f32>>,
+    model: String,
+    dimensions: usize,
+}
+
+// Request/response shape for Ollama's `/api/embeddings` endpoint
+#[derive(Debug, Serialize)]
+struct OllamaEmbeddingRequest {
+    model: String,
+    prompt: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaEmbeddingResponse {
+    embedding: Vec<f32>,
 }
 
 #[derive(Clone)]
 struct AppState {
     client: Client,
     ollama_url: String,
+    ollama_bearer_token: Option<String>,
+    generations: GenerationRegistry,
+    rate_limiter: Arc<GenerationRateLimiter>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AbortRequest {
+    generation_id: String,
+}
+
+// A registered generation's cancellation token, tagged with the IP that
+// started it (so `abort_generation` can't be used to cancel someone else's
+// in-flight request) and with a `registration` stamp unique to this one
+// registration (so a delayed unregister from a since-superseded
+// registration can't delete a different generation's entry - see
+// `unregister_generation`).
+struct GenerationHandle {
+    owner: IpAddr,
+    token: CancellationToken,
+    registration: Uuid,
+}
+
+// Register a fresh cancellation token for a generation, creating an
+// unguessable id when the caller didn't supply one (e.g. a `stream_response`
+// request without a `generation_id` query parameter), or when the caller-supplied id is already
+// registered - even to the same caller. Reusing a still-active id would
+// overwrite its `GenerationHandle` in the registry, stranding the original
+// generation's cancel token where nothing can reach it to abort it, so any
+// id currently in use is always rejected in favor of a fresh one.
+fn register_generation(
+    state: &AppState,
+    generation_id: Option<String>,
+    owner: IpAddr,
+) -> (String, CancellationToken, Uuid) {
+    let token = CancellationToken::new();
+    let registration = Uuid::new_v4();
+    let mut generations = state.generations.lock().unwrap();
+    let generation_id = match generation_id {
+        Some(id) if !generations.contains_key(&id) => id,
+        _ => format!("gen-{}", Uuid::new_v4()),
+    };
+    generations.insert(generation_id.clone(), GenerationHandle { owner, token: token.clone(), registration });
+    (generation_id, token, registration)
+}
+
+type GenerationRegistry = Arc<Mutex<HashMap<String, GenerationHandle>>>;
+
+// Removes a generation's entry only if it's still the exact registration
+// that created it. If the id has since been re-registered (its slot freed
+// and reused), the entry belongs to a different generation now, so this
+// leaves it alone rather than deleting it out from under a still-running
+// task.
+fn unregister_generation(generations: &GenerationRegistry, generation_id: &str, registration: Uuid) {
+    let mut generations = generations.lock().unwrap();
+    if generations.get(generation_id).map(|h| h.registration) == Some(registration) {
+        generations.remove(generation_id);
+    }
 }
 
 #[tokio::main]
@@ -85,11 +357,29 @@ async fn main() {
 
     // Get Ollama URL from environment or use default
     let ollama_url = get_ollama_url();
-    
+    let ollama_bearer_token = get_ollama_bearer_token();
+    let max_rps = get_max_requests_per_second();
+    let quota = Quota::with_period(Duration::from_secs_f64(1.0 / max_rps))
+        .expect("MAX_REQUESTS_PER_SECOND must be positive");
+
     // Application state
+    let rate_limiter = Arc::new(RateLimiter::keyed(quota));
     let state = Arc::new(AppState {
         client: Client::new(),
         ollama_url,
+        ollama_bearer_token,
+        generations: Arc::new(Mutex::new(HashMap::new())),
+        rate_limiter: rate_limiter.clone(),
+    });
+
+    // Periodically drop per-IP entries that haven't made a request recently
+    // so the limiter's map doesn't grow without bound.
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RATE_LIMITER_GC_INTERVAL);
+        loop {
+            interval.tick().await;
+            rate_limiter.retain_recent();
+        }
     });
 
     // Build our application with routes
@@ -98,11 +388,14 @@ async fn main() {
         .route("/api/config", get(get_config))
         .route("/api/models", get(get_models))
         .route("/api/generate", post(generate_response))
+        .route("/api/embeddings", post(embeddings))
         .route("/api/stream/:model/:prompt", get(stream_response))
         .route("/api/stream_with_files", post(stream_with_files))
+        .route("/api/chat", post(stream_chat))
         .route("/api/check_ollama", get(check_ollama))
         .route("/api/start_ollama", get(try_start_ollama))
         .route("/api/abort_generation", post(abort_generation))
+        .route("/api/pull", post(pull_model))
         .nest_service("/static", ServeDir::new("static"))
         .with_state(state)
         .layer(TraceLayer::new_for_http());
@@ -125,7 +418,7 @@ async fn main() {
     }
     
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
@@ -134,6 +427,22 @@ async fn serve_index() -> Html<&'static str> {
     Html(include_str!("../static/index.html"))
 }
 
+// Attach the `Authorization: Bearer <token>` header when one is configured
+fn with_auth(builder: reqwest::RequestBuilder, token: &Option<String>) -> reqwest::RequestBuilder {
+    match token {
+        Some(token) => builder.bearer_auth(token),
+        None => builder,
+    }
+}
+
+fn rate_limited_response() -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(serde_json::json!({ "error": "Too many requests, please slow down" })),
+    )
+        .into_response()
+}
+
 async fn get_config(
     State(state): State<Arc<AppState>>,
 ) -> Json<ConfigResponse> {
@@ -146,8 +455,11 @@ async fn check_ollama(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
     let client = &state.client;
-    
-    match client.get(&state.ollama_url).send().await {
+
+    match with_auth(client.get(&state.ollama_url), &state.ollama_bearer_token)
+        .send()
+        .await
+    {
         Ok(_) => Ok(Json(serde_json::json!({ "status": "running" }))),
         Err(_) => Ok(Json(serde_json::json!({ "status": "not_running" }))),
     }
@@ -172,9 +484,11 @@ async fn get_models(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<ModelsResponse>, StatusCode> {
     let client = &state.client;
-    
-    let response = client
-        .get(format!("{}/api/tags", state.ollama_url))
+
+    let response = with_auth(
+        client.get(format!("{}/api/tags", state.ollama_url)),
+        &state.ollama_bearer_token,
+    )
         .send()
         .await
         .map_err(|e| {
@@ -195,45 +509,161 @@ async fn get_models(
 
 async fn generate_response(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Json(request): Json<GenerateRequest>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
+) -> Response {
+    if state.rate_limiter.check_key(&addr.ip()).is_err() {
+        return rate_limited_response();
+    }
+
     let client = &state.client;
-    
+
     let ollama_request = OllamaRequest {
         model: request.model,
         prompt: request.prompt,
         stream: false,
+        system: request.system,
+        options: request.options.unwrap_or_default(),
     };
     
-    let response = client
-        .post(format!("{}/api/generate", state.ollama_url))
+    let response = match with_auth(
+        client.post(format!("{}/api/generate", state.ollama_url)),
+        &state.ollama_bearer_token,
+    )
         .json(&ollama_request)
         .send()
         .await
-        .map_err(|e| {
+    {
+        Ok(r) => r,
+        Err(e) => {
             tracing::error!("Error generating response: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-    
-    let response_data = response
-        .json::<serde_json::Value>()
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    match response.json::<serde_json::Value>().await {
+        Ok(response_data) => Json(response_data).into_response(),
+        Err(e) => {
+            tracing::error!("Error parsing generation response: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Request a single embedding vector from Ollama, shared by the `/api/embeddings`
+// route and the retrieval layer in `stream_with_files`.
+async fn fetch_embedding(
+    client: &Client,
+    ollama_url: &str,
+    ollama_bearer_token: &Option<String>,
+    model: &str,
+    prompt: &str,
+) -> Result<Vec<f32>, String> {
+    let ollama_request = OllamaEmbeddingRequest {
+        model: model.to_string(),
+        prompt: prompt.to_string(),
+    };
+
+    let response = with_auth(
+        client.post(format!("{}/api/embeddings", ollama_url)),
+        ollama_bearer_token,
+    )
+        .json(&ollama_request)
+        .send()
+        .await
+        .map_err(|e| format!("Error requesting embedding: {}", e))?;
+
+    response
+        .json::<OllamaEmbeddingResponse>()
+        .await
+        .map(|r| r.embedding)
+        .map_err(|e| format!("Error parsing embedding response: {}", e))
+}
+
+// Embed a batch of inputs, one Ollama request per input
+async fn embeddings(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, StatusCode> {
+    let mut vectors = Vec::with_capacity(request.input.len());
+
+    for input in &request.input {
+        let embedding = fetch_embedding(
+            &state.client,
+            &state.ollama_url,
+            &state.ollama_bearer_token,
+            &request.model,
+            input,
+        )
         .await
         .map_err(|e| {
-            tracing::error!("Error parsing generation response: {}", e);
+            tracing::error!("{}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
-    
-    Ok(Json(response_data))
+
+        vectors.push(embedding);
+    }
+
+    let dimensions = vectors.first().map(|v| v.len()).unwrap_or(0);
+
+    Ok(Json(EmbeddingResponse {
+        embeddings: vectors,
+        model: request.model,
+        dimensions,
+    }))
 }
 
 // Stream response from Ollama model
+// Query-string counterpart of `OllamaOptions`/`system` for this path-based
+// route, which has no JSON body to carry them in. `stop` takes a
+// comma-separated list since repeated query keys aren't worth the extra
+// parsing machinery for this one route.
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    generation_id: Option<String>,
+    system: Option<String>,
+    num_ctx: Option<u32>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    seed: Option<i64>,
+    stop: Option<String>,
+    num_predict: Option<i32>,
+}
+
+impl From<&StreamQuery> for OllamaOptions {
+    fn from(query: &StreamQuery) -> Self {
+        OllamaOptions {
+            num_ctx: query.num_ctx.unwrap_or_else(default_num_ctx),
+            temperature: query.temperature,
+            top_p: query.top_p,
+            seed: query.seed,
+            stop: query
+                .stop
+                .as_ref()
+                .map(|s| s.split(',').map(|part| part.trim().to_string()).collect()),
+            num_predict: query.num_predict,
+        }
+    }
+}
+
 async fn stream_response(
     Path((model, prompt)): Path<(String, String)>,
+    Query(query): Query<StreamQuery>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<Arc<AppState>>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+) -> Response {
+    if state.rate_limiter.check_key(&addr.ip()).is_err() {
+        return rate_limited_response();
+    }
+
     let client = state.client.clone();
     let ollama_url = state.ollama_url.clone();
-    
+    let ollama_bearer_token = state.ollama_bearer_token.clone();
+    let options = OllamaOptions::from(&query);
+    let system = query.system.clone();
+    let (generation_id, cancel_token, registration) = register_generation(&state, query.generation_id, addr.ip());
+    let generations = state.generations.clone();
+
     // Decode the URL-encoded prompt
     let decoded_prompt = match urlencoding::decode(&prompt) {
         Ok(decoded) => decoded.into_owned(),
@@ -242,44 +672,69 @@ async fn stream_response(
             prompt
         }
     };
-    
-    let (tx, rx) = tokio::sync::mpsc::channel(100);
-    
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event, Infallible>>(100);
+
     // Spawn a task to handle the streaming
     tokio::spawn(async move {
+        let _ = tx.send(Ok(Event::default().event("generation_id").data(generation_id.clone()))).await;
+
         let ollama_request = OllamaRequest {
             model,
             prompt: decoded_prompt,
             stream: true,
+            system,
+            options,
         };
-        
+
         // Log the request size for debugging
         let request_size = ollama_request.prompt.len();
         tracing::debug!("Request prompt size: {} bytes", request_size);
-        
-        let res = client
-            .post(format!("{}/api/generate", ollama_url))
+
+        let res = with_auth(
+            client.post(format!("{}/api/generate", ollama_url)),
+            &ollama_bearer_token,
+        )
             .json(&ollama_request)
             .send()
             .await;
-        
+
         let mut response = match res {
             Ok(r) => r,
             Err(e) => {
                 let _ = tx.send(Ok(Event::default().data(format!("Error: {}", e)))).await;
                 let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+                unregister_generation(&generations, &generation_id, registration);
                 return;
             }
         };
-        
+
         let mut buffer = Vec::new();
         let mut full_response = String::new();
-        
-        // Stream chunks of data
-        while let Some(chunk) = response.chunk().await.unwrap_or(None) {
+
+        // Stream chunks of data, bailing out promptly if the client aborted
+        // or the SSE receiver was dropped (tab closed / connection lost).
+        loop {
+            let chunk = tokio::select! {
+                biased;
+                _ = cancel_token.cancelled() => {
+                    tracing::info!("Generation {} cancelled by client", generation_id);
+                    unregister_generation(&generations, &generation_id, registration);
+                    return;
+                }
+                _ = tx.closed() => {
+                    tracing::info!("Receiver for generation {} dropped, cancelling upstream request", generation_id);
+                    unregister_generation(&generations, &generation_id, registration);
+                    return;
+                }
+                chunk = response.chunk() => chunk.unwrap_or(None),
+            };
+
+            let Some(chunk) = chunk else { break };
+
             tracing::debug!("Received raw chunk of size: {} bytes", chunk.len());
             buffer.extend_from_slice(&chunk);
-            
+
             // Process complete lines
             let mut start = 0;
             for i in 0..buffer.len() {
@@ -287,7 +742,7 @@ async fn stream_response(
                     if i > start {
                         let line = String::from_utf8_lossy(&buffer[start..i]).to_string();
                         start = i + 1;
-                        
+
                         // Parse JSON
                         match serde_json::from_str::<OllamaChunk>(&line) {
                             Ok(chunk) => {
@@ -295,7 +750,7 @@ async fn stream_response(
                                     tracing::debug!("Received response token: {}", response_text);
                                     // Add to full response
                                     full_response.push_str(&response_text);
-                                    
+
                                     // Send the full response so far, but only if it's not too long
                                     if full_response.len() < 100000 { // Limit to ~100KB per message
                                         tracing::debug!("Sending full response of length: {}", full_response.len());
@@ -306,10 +761,11 @@ async fn stream_response(
                                         let _ = tx.send(Ok(Event::default().data(response_text))).await;
                                     }
                                 }
-                                
+
                                 if chunk.done.unwrap_or(false) {
                                     tracing::info!("Generation completed, sending DONE signal");
                                     let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+                                    unregister_generation(&generations, &generation_id, registration);
                                     return;
                                 }
                             }
@@ -321,7 +777,7 @@ async fn stream_response(
                     }
                 }
             }
-            
+
             // Keep remaining data
             if start < buffer.len() {
                 buffer = buffer[start..].to_vec();
@@ -329,70 +785,154 @@ async fn stream_response(
                 buffer.clear();
             }
         }
-        
+
         let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+        unregister_generation(&generations, &generation_id, registration);
     });
-    
-    Sse::new(ReceiverStream::new(rx))
+
+    Sse::new(ReceiverStream::new(rx)).into_response()
 }
 
 // Stream response with file contexts using response streaming
 async fn stream_with_files(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Json(request): Json<StreamWithFilesRequest>,
-) -> impl IntoResponse {
+) -> Response {
+    if state.rate_limiter.check_key(&addr.ip()).is_err() {
+        return rate_limited_response();
+    }
+
     let client = state.client.clone();
     let ollama_url = state.ollama_url.clone();
-    
-    // Build a prompt with context from files
-    let mut context_prompt = String::new();
-    
-    // Add file contents as context
-    for file in &request.files {
-        context_prompt.push_str(&format!("File: {}\n```\n{}\n```\n\n", file.name, file.content));
-    }
-    
+    let ollama_bearer_token = state.ollama_bearer_token.clone();
+    let (generation_id, cancel_token, registration) = register_generation(&state, request.generation_id.clone(), addr.ip());
+    let generations = state.generations.clone();
+
     // Log information about the request first
-    tracing::info!("Processing request with {} files, prompt size: {} bytes", 
+    tracing::info!("Processing request with {} files, prompt size: {} bytes",
         request.files.len(), request.prompt.len());
-    
+
+    let max_context_chunks = request
+        .max_context_chunks
+        .unwrap_or(DEFAULT_MAX_CONTEXT_CHUNKS);
+
+    // Chunk each file up front; small files come back as a single whole chunk.
+    let file_chunks: Vec<(&str, String)> = request
+        .files
+        .iter()
+        .flat_map(|file| {
+            chunk_content(&file.content)
+                .into_iter()
+                .map(move |text| (file.name.as_str(), text))
+        })
+        .collect();
+
+    // If every chunk already fits within the chunk budget there's nothing to
+    // retrieve - include all of them. Small files are included whole; a file
+    // longer than `CHUNK_WORDS` appears as its overlapping word windows.
+    let context_prompt = if file_chunks.is_empty() {
+        String::new()
+    } else if file_chunks.len() <= max_context_chunks {
+        file_chunks
+            .iter()
+            .map(|(name, text)| format!("File: {}\n```\n{}\n```\n\n", name, text))
+            .collect::<String>()
+    } else {
+        let prompt_embedding = fetch_embedding(
+            &client,
+            &ollama_url,
+            &ollama_bearer_token,
+            &request.model,
+            &request.prompt,
+        )
+        .await;
+
+        match prompt_embedding {
+            Ok(prompt_embedding) => {
+                let mut scored: Vec<ScoredChunk> = Vec::with_capacity(file_chunks.len());
+                for (file_name, text) in &file_chunks {
+                    match fetch_embedding(&client, &ollama_url, &ollama_bearer_token, &request.model, text).await {
+                        Ok(embedding) => scored.push(ScoredChunk {
+                            file_name,
+                            text: text.clone(),
+                            score: cosine_similarity(&prompt_embedding, &embedding),
+                        }),
+                        Err(e) => tracing::error!("Error embedding chunk from {}: {}", file_name, e),
+                    }
+                }
+
+                if scored.is_empty() {
+                    tracing::warn!("All chunk embeddings failed; no file context will be included");
+                }
+
+                scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+                scored
+                    .into_iter()
+                    .take(max_context_chunks)
+                    .map(|chunk| format!("File: {}\n```\n{}\n```\n\n", chunk.file_name, chunk.text))
+                    .collect::<String>()
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Error embedding prompt, falling back to the first {} chunks: {}",
+                    max_context_chunks, e
+                );
+                file_chunks
+                    .iter()
+                    .take(max_context_chunks)
+                    .map(|(name, text)| format!("File: {}\n```\n{}\n```\n\n", name, text))
+                    .collect::<String>()
+            }
+        }
+    };
+
     // Then combine with user prompt
     let final_prompt = if !context_prompt.is_empty() {
         format!("I have the following files for context:\n\n{}\n\nBased on these files, {}", context_prompt, request.prompt)
     } else {
         request.prompt
     };
-    
+
     tracing::debug!("Request prompt size with files: {} bytes", final_prompt.len());
     
     // Create a channel for streaming responses with proper type annotation
     let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event, Infallible>>(100);
     let model = request.model;
-    
+    let system = request.system;
+    let options = request.options.unwrap_or_default();
+
     // Spawn a task to handle the streaming
     tokio::spawn(async move {
+        let _ = tx.send(Ok(Event::default().event("generation_id").data(generation_id.clone()))).await;
+
         let ollama_request = OllamaRequest {
             model,
             prompt: final_prompt,
             stream: true,
+            system,
+            options,
         };
-        
+
         // Make the request to Ollama
-        let res = client
-            .post(format!("{}/api/generate", ollama_url))
+        let res = with_auth(
+            client.post(format!("{}/api/generate", ollama_url)),
+            &ollama_bearer_token,
+        )
             .json(&ollama_request)
             .send()
             .await;
-        
+
         let res = match res {
             Ok(r) => r,
             Err(e) => {
                 let _ = tx.send(Ok(Event::default().data(format!("Error: {}", e)))).await;
                 let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+                unregister_generation(&generations, &generation_id, registration);
                 return;
             }
         };
-        
+
         // Check if the request was successful
         if !res.status().is_success() {
             let status = res.status();
@@ -400,18 +940,37 @@ async fn stream_with_files(
                 Ok(text) => text,
                 Err(_) => "Unknown error".to_string(),
             };
-            
+
             let _ = tx.send(Ok(Event::default().data(format!("Error: HTTP {} - {}", status, error_text)))).await;
             let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+            unregister_generation(&generations, &generation_id, registration);
             return;
         }
-        
+
         let mut response = res;
         let mut buffer = Vec::new();
         let mut full_response = String::new();
-        
-        // Stream chunks of data
-        while let Some(chunk) = response.chunk().await.unwrap_or(None) {
+
+        // Stream chunks of data, bailing out promptly if the client aborted
+        // or the SSE receiver was dropped (tab closed / connection lost).
+        loop {
+            let chunk = tokio::select! {
+                biased;
+                _ = cancel_token.cancelled() => {
+                    tracing::info!("Generation {} cancelled by client", generation_id);
+                    unregister_generation(&generations, &generation_id, registration);
+                    return;
+                }
+                _ = tx.closed() => {
+                    tracing::info!("Receiver for generation {} dropped, cancelling upstream request", generation_id);
+                    unregister_generation(&generations, &generation_id, registration);
+                    return;
+                }
+                chunk = response.chunk() => chunk.unwrap_or(None),
+            };
+
+            let Some(chunk) = chunk else { break };
+
             tracing::debug!("Received raw chunk of size: {} bytes", chunk.len());
             buffer.extend_from_slice(&chunk);
             
@@ -445,6 +1004,7 @@ async fn stream_with_files(
                                 if chunk.done.unwrap_or(false) {
                                     tracing::info!("Generation completed, sending DONE signal");
                                     let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+                                    unregister_generation(&generations, &generation_id, registration);
                                     return;
                                 }
                             }
@@ -456,7 +1016,7 @@ async fn stream_with_files(
                     }
                 }
             }
-            
+
             // Keep remaining data
             if start < buffer.len() {
                 buffer = buffer[start..].to_vec();
@@ -464,19 +1024,354 @@ async fn stream_with_files(
                 buffer.clear();
             }
         }
-        
+
         // Send any remaining response and done signal
         let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+        unregister_generation(&generations, &generation_id, registration);
     });
     
     // Return SSE stream from the receiver
+    Sse::new(ReceiverStream::new(rx)).into_response()
+}
+
+// Stream model download progress from Ollama's `/api/pull`
+async fn pull_model(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PullRequest>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let client = state.client.clone();
+    let ollama_url = state.ollama_url.clone();
+    let ollama_bearer_token = state.ollama_bearer_token.clone();
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event, Infallible>>(100);
+
+    tokio::spawn(async move {
+        let ollama_request = OllamaPullRequest { name: request.name };
+
+        let res = with_auth(
+            client.post(format!("{}/api/pull", ollama_url)),
+            &ollama_bearer_token,
+        )
+            .json(&ollama_request)
+            .send()
+            .await;
+
+        let mut response = match res {
+            Ok(r) => r,
+            Err(e) => {
+                let _ = tx.send(Ok(Event::default().data(format!("Error: {}", e)))).await;
+                let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+                return;
+            }
+        };
+
+        let mut buffer = Vec::new();
+
+        // Stream chunks of data, re-emitting each NDJSON progress object as-is
+        while let Some(chunk) = response.chunk().await.unwrap_or(None) {
+            buffer.extend_from_slice(&chunk);
+
+            let mut start = 0;
+            for i in 0..buffer.len() {
+                if buffer[i] == b'\n' {
+                    if i > start {
+                        let line = String::from_utf8_lossy(&buffer[start..i]).to_string();
+                        start = i + 1;
+
+                        tracing::debug!("Pull progress: {}", line);
+                        let _ = tx.send(Ok(Event::default().data(line))).await;
+                    }
+                }
+            }
+
+            if start < buffer.len() {
+                buffer = buffer[start..].to_vec();
+            } else {
+                buffer.clear();
+            }
+        }
+
+        let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+    });
+
     Sse::new(ReceiverStream::new(rx))
 }
 
+// Stream a multi-turn chat response from Ollama's `/api/chat`, preserving
+// role-separated conversation history instead of a single flat prompt.
+async fn stream_chat(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(request): Json<ChatRequest>,
+) -> Response {
+    if state.rate_limiter.check_key(&addr.ip()).is_err() {
+        return rate_limited_response();
+    }
+
+    let client = state.client.clone();
+    let ollama_url = state.ollama_url.clone();
+    let ollama_bearer_token = state.ollama_bearer_token.clone();
+    let (generation_id, cancel_token, registration) = register_generation(&state, request.generation_id.clone(), addr.ip());
+    let generations = state.generations.clone();
+
+    tracing::info!("Processing chat request with {} messages", request.messages.len());
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event, Infallible>>(100);
+    let model = request.model;
+    let messages = request.messages;
+    let options = request.options.unwrap_or_default();
+
+    tokio::spawn(async move {
+        let _ = tx.send(Ok(Event::default().event("generation_id").data(generation_id.clone()))).await;
+
+        let ollama_request = OllamaChatRequest {
+            model,
+            messages,
+            stream: true,
+            options,
+        };
+
+        let res = with_auth(
+            client.post(format!("{}/api/chat", ollama_url)),
+            &ollama_bearer_token,
+        )
+            .json(&ollama_request)
+            .send()
+            .await;
+
+        let mut response = match res {
+            Ok(r) => r,
+            Err(e) => {
+                let _ = tx.send(Ok(Event::default().data(format!("Error: {}", e)))).await;
+                let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+                unregister_generation(&generations, &generation_id, registration);
+                return;
+            }
+        };
+
+        let mut buffer = Vec::new();
+        let mut full_response = String::new();
+
+        // Bail out promptly if the client aborted or the SSE receiver was
+        // dropped (tab closed / connection lost).
+        loop {
+            let chunk = tokio::select! {
+                biased;
+                _ = cancel_token.cancelled() => {
+                    tracing::info!("Generation {} cancelled by client", generation_id);
+                    unregister_generation(&generations, &generation_id, registration);
+                    return;
+                }
+                _ = tx.closed() => {
+                    tracing::info!("Receiver for generation {} dropped, cancelling upstream request", generation_id);
+                    unregister_generation(&generations, &generation_id, registration);
+                    return;
+                }
+                chunk = response.chunk() => chunk.unwrap_or(None),
+            };
+
+            let Some(chunk) = chunk else { break };
+
+            buffer.extend_from_slice(&chunk);
+
+            let mut start = 0;
+            for i in 0..buffer.len() {
+                if buffer[i] == b'\n' {
+                    if i > start {
+                        let line = String::from_utf8_lossy(&buffer[start..i]).to_string();
+                        start = i + 1;
+
+                        match serde_json::from_str::<OllamaChatChunk>(&line) {
+                            Ok(chunk) => {
+                                if let Some(content) = chunk.message.and_then(|m| m.content) {
+                                    full_response.push_str(&content);
+
+                                    if full_response.len() < 100000 {
+                                        let _ = tx.send(Ok(Event::default().data(full_response.clone()))).await;
+                                    } else {
+                                        let _ = tx.send(Ok(Event::default().data(content))).await;
+                                    }
+                                }
+
+                                if chunk.done.unwrap_or(false) {
+                                    tracing::info!("Chat generation completed, sending DONE signal");
+                                    let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+                                    unregister_generation(&generations, &generation_id, registration);
+                                    return;
+                                }
+                            }
+                            Err(e) => {
+                                tracing::error!("Error parsing JSON: {}\nJSON was: {}", e, line);
+                                let _ = tx.send(Ok(Event::default().data(format!("Error parsing JSON: {}", e)))).await;
+                            }
+                        }
+                    }
+                }
+            }
+
+            if start < buffer.len() {
+                buffer = buffer[start..].to_vec();
+            } else {
+                buffer.clear();
+            }
+        }
+
+        let _ = tx.send(Ok(Event::default().data("[DONE]"))).await;
+        unregister_generation(&generations, &generation_id, registration);
+    });
+
+    Sse::new(ReceiverStream::new(rx)).into_response()
+}
+
 // Handle abort notifications from client
-async fn abort_generation() -> Result<Json<serde_json::Value>, StatusCode> {
-    // We don't need to do much here since the client already closed the connection
-    // This endpoint mainly serves as a way to log aborts if needed
-    tracing::info!("Generation aborted by client");
-    Ok(Json(serde_json::json!({ "status": "aborted" })))
+async fn abort_generation(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(request): Json<AbortRequest>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    // Only the client that started a generation may cancel it - otherwise any
+    // client could enumerate ids and cancel another client's in-flight request.
+    let mut generations = state.generations.lock().unwrap();
+    let cancelled = match generations.get(&request.generation_id) {
+        Some(handle) if handle.owner == addr.ip() => {
+            handle.token.cancel();
+            generations.remove(&request.generation_id);
+            true
+        }
+        Some(_) => {
+            tracing::warn!(
+                "Refusing abort of generation {} requested by {}: not the owner",
+                request.generation_id,
+                addr.ip()
+            );
+            false
+        }
+        None => {
+            tracing::warn!("Abort requested for unknown generation {}", request.generation_id);
+            false
+        }
+    };
+    drop(generations);
+
+    if cancelled {
+        tracing::info!("Generation {} aborted by client", request.generation_id);
+    }
+
+    Ok(Json(serde_json::json!({ "status": "aborted", "cancelled": cancelled })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_content_passes_short_file_through_as_a_single_chunk() {
+        let content = "one two three four five";
+        let chunks = chunk_content(content);
+        assert_eq!(chunks, vec![content.to_string()]);
+    }
+
+    #[test]
+    fn chunk_content_splits_long_files_into_overlapping_windows() {
+        let words: Vec<String> = (0..1200).map(|i| i.to_string()).collect();
+        let content = words.join(" ");
+        let chunks = chunk_content(&content);
+
+        // stride = CHUNK_WORDS - CHUNK_OVERLAP_WORDS = 450, so windows start
+        // at 0, 450, 900 before the final window is clamped to the end.
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].split_whitespace().next(), Some("0"));
+        assert_eq!(chunks[0].split_whitespace().last(), Some("499"));
+        // Consecutive windows overlap by CHUNK_OVERLAP_WORDS words.
+        assert_eq!(chunks[1].split_whitespace().next(), Some("450"));
+        assert_eq!(chunks[1].split_whitespace().last(), Some("949"));
+        // The final window ends exactly at the last word, whatever its start.
+        assert_eq!(chunks.last().unwrap().split_whitespace().last(), Some("1199"));
+    }
+
+    #[test]
+    fn cosine_similarity_of_identical_vectors_is_one() {
+        let v = vec![1.0, 2.0, 3.0];
+        assert!((cosine_similarity(&v, &v) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cosine_similarity_with_a_zero_vector_is_zero() {
+        let zero = vec![0.0, 0.0, 0.0];
+        let v = vec![1.0, 2.0, 3.0];
+        assert_eq!(cosine_similarity(&zero, &v), 0.0);
+        assert_eq!(cosine_similarity(&v, &zero), 0.0);
+        assert_eq!(cosine_similarity(&zero, &zero), 0.0);
+    }
+
+    fn test_state() -> AppState {
+        let quota = Quota::with_period(Duration::from_secs_f64(0.5)).unwrap();
+        AppState {
+            client: Client::new(),
+            ollama_url: "http://127.0.0.1:11434".to_string(),
+            ollama_bearer_token: None,
+            generations: Arc::new(Mutex::new(HashMap::new())),
+            rate_limiter: Arc::new(RateLimiter::keyed(quota)),
+        }
+    }
+
+    #[test]
+    fn register_generation_mints_a_fresh_id_when_the_caller_supplies_none() {
+        let state = test_state();
+        let owner: IpAddr = "127.0.0.1".parse().unwrap();
+        let (id, _token, _registration) = register_generation(&state, None, owner);
+        assert!(state.generations.lock().unwrap().contains_key(&id));
+    }
+
+    #[test]
+    fn register_generation_rejects_reuse_of_a_still_active_id_by_the_same_owner() {
+        let state = test_state();
+        let owner: IpAddr = "127.0.0.1".parse().unwrap();
+        let (id, _first_token, first_registration) =
+            register_generation(&state, Some("caller-chosen".to_string()), owner);
+
+        // The id is still registered (the first generation hasn't finished),
+        // so a second registration under the same owner must not reuse it -
+        // reusing it would overwrite the first entry and strand its token.
+        let (second_id, _second_token, second_registration) =
+            register_generation(&state, Some("caller-chosen".to_string()), owner);
+
+        assert_ne!(second_id, "caller-chosen");
+        assert_ne!(first_registration, second_registration);
+        let generations = state.generations.lock().unwrap();
+        assert_eq!(generations.get(&id).unwrap().registration, first_registration);
+        assert_eq!(generations.get(&second_id).unwrap().registration, second_registration);
+    }
+
+    #[test]
+    fn register_generation_rejects_reuse_of_a_still_active_id_by_a_different_owner() {
+        let state = test_state();
+        let owner: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        register_generation(&state, Some("caller-chosen".to_string()), owner);
+
+        let (second_id, ..) = register_generation(&state, Some("caller-chosen".to_string()), other);
+        assert_ne!(second_id, "caller-chosen");
+    }
+
+    #[test]
+    fn unregister_generation_does_not_delete_an_entry_from_a_newer_registration() {
+        let state = test_state();
+        let owner: IpAddr = "127.0.0.1".parse().unwrap();
+        let (id, _first_token, first_registration) =
+            register_generation(&state, Some("caller-chosen".to_string()), owner);
+        unregister_generation(&state.generations, &id, first_registration);
+
+        // The slot is free again, so this legitimately reuses the id under a
+        // fresh registration.
+        let (second_id, _second_token, second_registration) =
+            register_generation(&state, Some(id.clone()), owner);
+        assert_eq!(second_id, id);
+
+        // A delayed unregister carrying the stale, superseded registration
+        // must not remove the newer entry.
+        unregister_generation(&state.generations, &id, first_registration);
+        let generations = state.generations.lock().unwrap();
+        assert_eq!(generations.get(&id).unwrap().registration, second_registration);
+    }
 }